@@ -1,6 +1,10 @@
 use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use mqtt_broker::auth::{Activity, Authorizer, MakeAuthorizer};
+use mqtt_opa_watch::PolicyWatcher;
 use opa_wasm::Policy;
 
 #[derive(Debug)]
@@ -18,13 +22,39 @@ impl std::error::Error for Error {
     }
 }
 
+enum Source {
+    /// Precompiled wasm bytes with no source file to watch for changes.
+    Bytes,
+    /// A `.rego` source file, recompiled with `opa_go::wasm::compile` whenever it changes.
+    Path { query: String, path: PathBuf },
+}
+
 pub struct MakeOpaAuthorizer {
     module: Vec<u8>,
+    source: Source,
 }
 
 impl MakeOpaAuthorizer {
     pub fn from_bytes(bytes: Vec<u8>) -> Result<MakeOpaAuthorizer, Error> {
-        let auth = Self { module: bytes };
+        let auth = Self {
+            module: bytes,
+            source: Source::Bytes,
+        };
+        Ok(auth)
+    }
+
+    /// Compiles the `.rego` policy at `path` and, unlike [`MakeOpaAuthorizer::from_bytes`],
+    /// keeps watching it: the resulting authorizer hot-reloads whenever the file changes.
+    pub fn from_path<P: AsRef<Path>>(query: &str, path: P) -> Result<MakeOpaAuthorizer, Error> {
+        let path = path.as_ref().to_path_buf();
+        let module = opa_go::wasm::compile(query, &path).unwrap();
+        let auth = Self {
+            module,
+            source: Source::Path {
+                query: query.to_owned(),
+                path,
+            },
+        };
         Ok(auth)
     }
 }
@@ -34,18 +64,38 @@ impl MakeAuthorizer for MakeOpaAuthorizer {
     type Error = Error;
 
     fn make_authorizer(self) -> Result<Self::Authorizer, Self::Error> {
-        OpaAuthorizer::from_bytes(&self.module)
+        OpaAuthorizer::new(&self.module, self.source)
     }
 }
 
+/// An OPA-backed authorizer whose compiled policy can be swapped out while the broker is
+/// running, when it was built with [`MakeOpaAuthorizer::from_path`].
+///
+/// `authorize` reads the policy through an `ArcSwap`, so a background task (see
+/// [`mqtt_opa_watch::PolicyWatcher`]) can watch the source `.rego` file, recompile it on
+/// change, and atomically publish the new policy with zero dropped connections.
 pub struct OpaAuthorizer {
-    policy: Policy,
+    policy: Arc<ArcSwap<Policy>>,
+    _watcher: Option<PolicyWatcher>,
 }
 
 impl OpaAuthorizer {
-    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+    fn new(bytes: &[u8], source: Source) -> Result<Self, Error> {
         let policy = Policy::from_wasm(bytes).unwrap();
-        let auth = Self { policy };
+        let policy = Arc::new(ArcSwap::from_pointee(policy));
+        let watcher = match source {
+            Source::Bytes => None,
+            Source::Path { query, path } => Some(PolicyWatcher::spawn(
+                query,
+                path,
+                Arc::clone(&policy),
+                |q, p| opa_go::wasm::compile(q, p).map_err(|e| e.to_string()),
+            )),
+        };
+        let auth = Self {
+            policy,
+            _watcher: watcher,
+        };
         Ok(auth)
     }
 }
@@ -54,7 +104,8 @@ impl Authorizer for OpaAuthorizer {
     type Error = Error;
 
     fn authorize(&mut self, activity: Activity) -> Result<bool, Self::Error> {
-        let value = self.policy.evaluate(&activity).unwrap();
+        let policy = self.policy.load();
+        let value = policy.evaluate(&activity).unwrap();
         Ok(!value.try_into_set().unwrap().is_empty())
     }
 }
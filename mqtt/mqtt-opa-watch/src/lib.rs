@@ -0,0 +1,127 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
+use std::thread;
+
+use arc_swap::ArcSwap;
+use mqtt_broker::auth::Activity;
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use opa_wasm::Policy;
+use tracing::{debug, error, info};
+
+/// Why a policy hot-reload failed, so the log line at the point of failure carries the
+/// actual compile/load/evaluate error rather than a generic message.
+#[derive(Debug)]
+pub enum ReloadError {
+    Compile(String),
+    Load(String),
+    Evaluate(String),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::Compile(e) => write!(f, "failed to compile policy: {}", e),
+            ReloadError::Load(e) => write!(f, "failed to load policy: {}", e),
+            ReloadError::Evaluate(e) => write!(f, "failed to evaluate probe activity: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+/// Watches a policy's source `.rego` file and hot-swaps the compiled `Policy` into the
+/// authorizer's `ArcSwap` cell whenever the file changes, without restarting the broker or
+/// dropping any sessions.
+///
+/// Shared by every OPA-backed authorizer crate (`mqtt-opa`, `mqtt-opa-wasm`) so the watch
+/// loop and its reload/error-handling only need to live in one place; `compile` is the
+/// backend-specific step that turns a `.rego` file into wasm bytes.
+///
+/// The watcher never publishes a policy that fails to compile or fails to evaluate against
+/// a harmless probe activity; on either failure it logs the real underlying error and
+/// leaves the previous policy live. If the initial `watch()` registration itself fails, no
+/// background thread is spawned at all, rather than silently leaving hot-reload inert.
+pub struct PolicyWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl PolicyWatcher {
+    pub fn spawn<C>(query: String, path: PathBuf, policy: Arc<ArcSwap<Policy>>, compile: C) -> Self
+    where
+        C: Fn(&str, &Path) -> Result<Vec<u8>, String> + Send + 'static,
+    {
+        let (tx, rx) = channel();
+        let mut watcher: RecommendedWatcher = watcher(tx, Duration::from_secs(1))
+            .expect("failed to create policy file watcher");
+        if let Err(e) = watcher.watch(&path, RecursiveMode::NonRecursive) {
+            error!(
+                message = "failed to watch policy file for changes, hot-reload disabled",
+                file = %path.display(),
+                error = %e
+            );
+            return Self { _watcher: watcher };
+        }
+
+        thread::spawn(move || {
+            for event in rx {
+                match event {
+                    DebouncedEvent::Write(_)
+                    | DebouncedEvent::Create(_)
+                    | DebouncedEvent::Chmod(_) => {
+                        debug!(message = "policy file changed, recompiling", file = %path.display());
+                        match reload(&query, &path, &compile) {
+                            Ok(new_policy) => {
+                                policy.store(Arc::new(new_policy));
+                                info!(message = "reloaded policy", file = %path.display());
+                            }
+                            Err(e) => {
+                                error!(
+                                    message = "failed to reload policy, keeping previous policy live",
+                                    file = %path.display(),
+                                    error = %e
+                                );
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Self { _watcher: watcher }
+    }
+}
+
+/// Recompiles the policy at `path` via `compile` and validates it by evaluating a harmless
+/// probe `Activity`, so a policy that compiles but can't actually be evaluated never gets
+/// published.
+fn reload(
+    query: &str,
+    path: &Path,
+    compile: &impl Fn(&str, &Path) -> Result<Vec<u8>, String>,
+) -> Result<Policy, ReloadError> {
+    let module = compile(query, path).map_err(ReloadError::Compile)?;
+    let policy = Policy::from_wasm(&module).map_err(|e| ReloadError::Load(e.to_string()))?;
+    policy
+        .evaluate(&Activity::default())
+        .map_err(|e| ReloadError::Evaluate(e.to_string()))?;
+    Ok(policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_surfaces_compile_error() {
+        let err = reload("data.mqtt.allow", Path::new("policy.rego"), &|_, _| {
+            Err("unexpected token".to_owned())
+        })
+        .unwrap_err();
+
+        assert_eq!(err.to_string(), "failed to compile policy: unexpected token");
+    }
+}
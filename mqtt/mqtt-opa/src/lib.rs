@@ -1,7 +1,10 @@
 use std::fmt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use mqtt_broker::auth::{Activity, Authorizer, MakeAuthorizer};
+use mqtt_opa_watch::PolicyWatcher;
 use opa_wasm::Policy;
 
 #[derive(Debug)]
@@ -20,13 +23,20 @@ impl std::error::Error for Error {
 }
 
 pub struct MakeOpaAuthorizer {
+    query: String,
+    path: PathBuf,
     module: Vec<u8>,
 }
 
 impl MakeOpaAuthorizer {
     pub fn from_rego<P: AsRef<Path>>(query: &str, path: P) -> Result<MakeOpaAuthorizer, Error> {
-        let module = opa_compiler::compile(query, path).unwrap();
-        let auth = Self { module };
+        let path = path.as_ref().to_path_buf();
+        let module = opa_compiler::compile(query, &path).unwrap();
+        let auth = Self {
+            query: query.to_owned(),
+            path,
+            module,
+        };
         Ok(auth)
     }
 }
@@ -36,18 +46,34 @@ impl MakeAuthorizer for MakeOpaAuthorizer {
     type Error = Error;
 
     fn make_authorizer(self) -> Result<Self::Authorizer, Self::Error> {
-        OpaAuthorizer::from_bytes(&self.module)
+        OpaAuthorizer::from_bytes(self.query, self.path, &self.module)
     }
 }
 
+/// An OPA-backed authorizer whose compiled policy can be swapped out while the broker is
+/// running.
+///
+/// `authorize` reads the policy through an `ArcSwap`, so a background task (see
+/// [`mqtt_opa_watch::PolicyWatcher`]) can watch the source `.rego` file, recompile it on
+/// change, and atomically publish the new policy with zero dropped connections: in-flight
+/// and subsequent `authorize` calls just read whichever policy is current at the moment
+/// they run.
 pub struct OpaAuthorizer {
-    policy: Policy,
+    policy: Arc<ArcSwap<Policy>>,
+    _watcher: PolicyWatcher,
 }
 
 impl OpaAuthorizer {
-    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+    fn from_bytes(query: String, path: PathBuf, bytes: &[u8]) -> Result<Self, Error> {
         let policy = Policy::from_wasm(bytes).unwrap();
-        let auth = Self { policy };
+        let policy = Arc::new(ArcSwap::from_pointee(policy));
+        let watcher = PolicyWatcher::spawn(query, path, Arc::clone(&policy), |q, p| {
+            opa_compiler::compile(q, p).map_err(|e| e.to_string())
+        });
+        let auth = Self {
+            policy,
+            _watcher: watcher,
+        };
         Ok(auth)
     }
 }
@@ -56,7 +82,8 @@ impl Authorizer for OpaAuthorizer {
     type Error = Error;
 
     fn authorize(&mut self, activity: Activity) -> Result<bool, Self::Error> {
-        let value = self.policy.evaluate(&activity).unwrap();
+        let policy = self.policy.load();
+        let value = policy.evaluate(&activity).unwrap();
         Ok(!value.try_into_set().unwrap().is_empty())
     }
 }
@@ -0,0 +1,122 @@
+use std::fmt;
+
+use mqtt_broker::{AuthId, AuthenticationContext};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+
+/// Maps the mTLS peer certificate on an `AuthenticationContext`, if any, to a
+/// `CertificateIdentity`.
+///
+/// Returns `Ok(None)` only when the connection didn't present a client certificate at all,
+/// leaving the caller to decide how to treat that; a certificate that was presented but is
+/// unusable (fails to parse, or has no subject common name) is always an `Err`, never folded
+/// into the no-certificate case.
+///
+/// Scope of what this module does and doesn't cover:
+/// - It only maps an already-negotiated peer certificate to an identity; it does not
+///   configure any transport to request or terminate client certificates in the first
+///   place. That's `config.transports()`/the transport construction in `mqtt-broker`, which
+///   isn't part of this crate - `context.certificate()` only returns `Some` once a transport
+///   upstream is actually set up to do the TLS handshake with client-cert verification.
+/// - `CertificateIdentity` carries the SubjectAltName entries and SHA-256 thumbprint
+///   alongside the `AuthId` so a caller has them available to pass downstream (e.g. into an
+///   authorizer's `Activity`, for Rego/WASM policies to make per-device decisions on more
+///   than the bare device id). This module does not do that threading itself: `Activity` and
+///   the `Authorizer` trait it's threaded through aren't part of this crate either, and
+///   `mqttd::main` currently only forwards `auth_id` to the broker. Wiring the rest through
+///   is follow-up work in `mqtt-broker`/`mqtt-opa*`, not something achievable from here.
+pub fn authenticate(
+    context: &AuthenticationContext,
+) -> Result<Option<CertificateIdentity>, CertificateError> {
+    let certificate = match context.certificate() {
+        Some(certificate) => certificate,
+        None => return Ok(None),
+    };
+
+    let (_, cert) =
+        X509Certificate::from_der(certificate.as_ref()).map_err(|_| CertificateError::Parse)?;
+
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_owned);
+
+    let alt_names: Vec<String> = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|ext| {
+            ext.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some((*dns).to_owned()),
+                    GeneralName::URI(uri) => Some((*uri).to_owned()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(certificate.as_ref());
+    let thumbprint = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    let device_id = match common_name {
+        Some(device_id) => device_id,
+        None => return Err(CertificateError::NoCommonName { thumbprint }),
+    };
+
+    debug!(
+        message = "authenticated client certificate",
+        device_id = %device_id,
+        alt_names = ?alt_names,
+        thumbprint = %thumbprint
+    );
+    Ok(Some(CertificateIdentity {
+        auth_id: AuthId::from(device_id),
+        thumbprint,
+        alt_names,
+    }))
+}
+
+/// The identity extracted from a peer's mTLS client certificate.
+///
+/// `auth_id` is derived only from the subject common name. `thumbprint` and `alt_names` are
+/// carried alongside it, not folded into `auth_id`, so a caller that wants to surface them to
+/// an authorizer (rather than just logging them) has them available to do so.
+#[derive(Debug, Clone)]
+pub struct CertificateIdentity {
+    pub auth_id: AuthId,
+    pub thumbprint: String,
+    pub alt_names: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum CertificateError {
+    Parse,
+    NoCommonName { thumbprint: String },
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateError::Parse => write!(f, "failed to parse peer certificate"),
+            CertificateError::NoCommonName { thumbprint } => write!(
+                f,
+                "peer certificate has no subject common name, thumbprint={}",
+                thumbprint
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
@@ -3,13 +3,15 @@ use std::{convert::TryInto, env, io};
 use clap::{crate_description, crate_name, crate_version, App, Arg};
 use futures_util::pin_mut;
 use mqtt_broker::*;
-use mqtt_opa::MakeOpaAuthorizer;
+use mqtt_opa_wasm::MakeOpaAuthorizer;
 use tokio::time::{Duration, Instant};
 use tracing::{info, warn, Level};
 use tracing_subscriber::{fmt, EnvFilter};
 
 use mqttd::{shutdown, snapshot, Terminate};
 
+mod cert;
+
 #[tokio::main]
 async fn main() -> Result<(), Terminate> {
     let subscriber = fmt::Subscriber::builder()
@@ -35,8 +37,9 @@ async fn run() -> Result<(), Error> {
     let shutdown = shutdown::shutdown();
     pin_mut!(shutdown);
 
-    let wasm_bytes = opa_go::wasm::compile("data.mqtt.allow", "policy.rego").unwrap();
-    let authorizer = MakeOpaAuthorizer::from_bytes(wasm_bytes).unwrap();
+    // `from_path` keeps watching "policy.rego" and hot-reloads the compiled policy on
+    // change, so updating authorization rules no longer requires restarting the broker.
+    let authorizer = MakeOpaAuthorizer::from_path("data.mqtt.allow", "policy.rego").unwrap();
 
     // Setup the snapshotter
     let mut persistor = FilePersistor::new(
@@ -44,9 +47,23 @@ async fn run() -> Result<(), Error> {
         ConsolidatedStateFormat::default(),
     );
     info!("Loading state...");
-    let state = persistor.load()?.unwrap_or_else(BrokerState::default);
+    let state = persistor.load().await?.unwrap_or_else(BrokerState::default);
     let broker = BrokerBuilder::default()
-        .authenticator(|_| Ok(Some(AuthId::Anonymous)))
+        .authenticator(|context| match cert::authenticate(&context) {
+            // `identity.thumbprint`/`identity.alt_names` aren't forwarded any further than
+            // this - see the scope note on `cert::authenticate` for why.
+            Ok(Some(identity)) => Ok(Some(identity.auth_id)),
+            // No client certificate was presented at all - fall back to anonymous.
+            Ok(None) => Ok(Some(AuthId::Anonymous)),
+            // A client certificate was presented but couldn't be authenticated (parse
+            // failure, or no usable identity in it). Unlike the no-cert case, this means the
+            // peer did try to authenticate and failed, so fail closed (reject the
+            // connection) rather than silently downgrading to anonymous access.
+            Err(e) => {
+                warn!(message = "rejecting connection with unusable client certificate", error = %e);
+                Ok(None)
+            }
+        })
         .authorizer(authorizer)
         .state(state)
         .build();
@@ -87,7 +104,7 @@ async fn run() -> Result<(), Error> {
     info!("state snapshotter shutdown.");
 
     info!("persisting state before exiting...");
-    persistor.store(state)?;
+    persistor.store(state).await?;
     info!("state persisted.");
     info!("exiting... goodbye");
 